@@ -0,0 +1,155 @@
+//! Bearer token authentication for the admin routes
+
+use axum::{
+    body::BoxBody,
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderValue, Request, Response, StatusCode,
+    },
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tower_http::validate_request::ValidateRequest;
+
+/// Validates `Authorization: Bearer <token>` against the configured token.
+///
+/// Every failure is a `401`, but a missing header, a header that isn't a
+/// bearer credential and a wrong token each carry their own error code so
+/// clients can tell "you forgot the key" from "your key is bad".
+#[derive(Clone)]
+pub struct BearerAuth {
+    token: Arc<str>,
+}
+
+impl BearerAuth {
+    pub fn new(token: &str) -> Self {
+        Self {
+            token: token.into(),
+        }
+    }
+}
+
+impl<B> ValidateRequest<B> for BearerAuth {
+    type ResponseBody = BoxBody;
+
+    fn validate(&mut self, request: &mut Request<B>) -> Result<(), Response<Self::ResponseBody>> {
+        let Some(header) = request.headers().get(AUTHORIZATION) else {
+            return Err(unauthorized(
+                "Bearer",
+                "missing_credentials",
+                "authorization header is missing",
+            ));
+        };
+
+        let Some(token) = header.to_str().ok().and_then(bearer_token) else {
+            return Err(unauthorized(
+                r#"Bearer error="invalid_request""#,
+                "malformed_credentials",
+                "authorization header must be `Bearer <token>`",
+            ));
+        };
+
+        if constant_time_eq(token.as_bytes(), self.token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(unauthorized(
+                r#"Bearer error="invalid_token""#,
+                "invalid_credentials",
+                "bearer token is not valid",
+            ))
+        }
+    }
+}
+
+fn unauthorized(challenge: &'static str, code: &str, message: &str) -> Response<BoxBody> {
+    let mut response = (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({ "error": code, "message": message })),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+    response
+}
+
+/// The token of a `Bearer` credential. The scheme is case-insensitive.
+fn bearer_token(value: &str) -> Option<&str> {
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim_start();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+/// Compare without short-circuiting, so timing doesn't reveal how much of a
+/// guessed token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rejection for a request with this `Authorization` header, if any.
+    fn rejection(authorization: Option<&str>) -> Option<Response<BoxBody>> {
+        let mut request = Request::builder();
+        if let Some(value) = authorization {
+            request = request.header(AUTHORIZATION, value);
+        }
+        BearerAuth::new("secret-token")
+            .validate(&mut request.body(()).unwrap())
+            .err()
+    }
+
+    async fn assert_rejected(authorization: Option<&str>, challenge: &str, code: &str) {
+        let response = rejection(authorization).expect("request should be rejected");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], challenge);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], code);
+    }
+
+    #[tokio::test]
+    async fn missing_header() {
+        assert_rejected(None, "Bearer", "missing_credentials").await;
+    }
+
+    #[tokio::test]
+    async fn malformed_header() {
+        for value in ["Basic c2VjcmV0", "secret-token", "Bearer ", "Bearer"] {
+            assert_rejected(
+                Some(value),
+                r#"Bearer error="invalid_request""#,
+                "malformed_credentials",
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_token() {
+        for value in ["Bearer nope", "Bearer secret-token-2", "Bearer secret-toke"] {
+            assert_rejected(
+                Some(value),
+                r#"Bearer error="invalid_token""#,
+                "invalid_credentials",
+            )
+            .await;
+        }
+    }
+
+    #[test]
+    fn valid_token_with_any_scheme_case() {
+        for value in [
+            "Bearer secret-token",
+            "bearer secret-token",
+            "BEARER  secret-token",
+        ] {
+            assert!(rejection(Some(value)).is_none(), "{value:?}");
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Debug logging of request and response bodies, for webhook integrations
+
+use axum::{
+    body::{self, Body, Bytes, HttpBody},
+    extract::State,
+    http::{header, HeaderMap, HeaderName, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Headers whose values never reach the logs
+const SENSITIVE_HEADERS: [HeaderName; 5] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+    // Telegram's webhook secret, set with `setWebhook`
+    HeaderName::from_static("x-telegram-bot-api-secret-token"),
+];
+
+#[derive(Clone, Copy)]
+pub struct BodyLog {
+    /// Whether to log at all, from `LOG_BODIES`
+    pub enabled: bool,
+    /// Bytes of each body written to the log, the rest is elided
+    pub log_limit: usize,
+    /// Largest body buffered, matching the router's request body limit
+    pub max_body_bytes: usize,
+}
+
+/// Middleware logging method, path, status, redacted headers and truncated
+/// bodies at debug level. Both bodies are buffered while enabled, so requests
+/// pass straight through unless `LOG_BODIES` is on. Responses that may be
+/// larger than `max_body_bytes`, including streamed ones, are not buffered and
+/// their bodies are not logged.
+pub async fn log_bodies(
+    State(config): State<BodyLog>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if !config.enabled {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    // Runs before the per-route timeouts, under the router-wide backstop
+    let bytes = match buffer(body, config.max_body_bytes).await {
+        Ok(bytes) => bytes,
+        Err(BufferError::TooLarge) => {
+            return (StatusCode::PAYLOAD_TOO_LARGE, "length limit exceeded").into_response();
+        }
+        Err(BufferError::Read(error)) => {
+            tracing::debug!(path = parts.uri.path(), %error, "request body failed");
+            return (StatusCode::BAD_REQUEST, "failed to read request body").into_response();
+        }
+    };
+    tracing::debug!(
+        method = %parts.method,
+        path = parts.uri.path(),
+        headers = ?redacted(&parts.headers),
+        body = %truncated(&bytes, config.log_limit),
+        "request",
+    );
+
+    let response = next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await;
+
+    let (parts, body) = response.into_parts();
+    let within_limit = body
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size <= config.max_body_bytes as u64);
+    if !within_limit {
+        tracing::debug!(
+            status = %parts.status,
+            headers = ?redacted(&parts.headers),
+            body = "<not logged>",
+            "response",
+        );
+        return Response::from_parts(parts, body);
+    }
+
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            tracing::debug!(status = %parts.status, %error, "response body failed");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    tracing::debug!(
+        status = %parts.status,
+        headers = ?redacted(&parts.headers),
+        body = %truncated(&bytes, config.log_limit),
+        "response",
+    );
+    Response::from_parts(parts, body::boxed(body::Full::from(bytes)))
+}
+
+enum BufferError {
+    /// The body grew past the limit
+    TooLarge,
+    /// The body could not be read, e.g. the client disconnected
+    Read(hyper::Error),
+}
+
+/// Collect `body`, failing once it grows past `limit` bytes.
+async fn buffer(mut body: Body, limit: usize) -> Result<Bytes, BufferError> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(BufferError::Read)?;
+        if buf.len() + chunk.len() > limit {
+            return Err(BufferError::TooLarge);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.into())
+}
+
+fn redacted(headers: &HeaderMap) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SENSITIVE_HEADERS.contains(name) {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<binary>")
+            };
+            (name.as_str(), value)
+        })
+        .collect()
+}
+
+fn truncated(bytes: &[u8], limit: usize) -> String {
+    if bytes.len() <= limit {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    format!(
+        "{}... ({} bytes total)",
+        String::from_utf8_lossy(&bytes[..limit]),
+        bytes.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::CapturedLogs;
+    use axum::{
+        middleware,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+    use tracing::subscriber::DefaultGuard;
+
+    fn test_app() -> Router {
+        Router::new()
+            .route("/webhook", post(|body: Bytes| async move { body }))
+            .route("/large", get(|| async { "x".repeat(2048) }))
+            .layer(middleware::from_fn_with_state(
+                BodyLog {
+                    enabled: true,
+                    log_limit: 64,
+                    max_body_bytes: 1024,
+                },
+                log_bodies,
+            ))
+    }
+
+    /// Capture debug logs on this thread until the guard is dropped.
+    fn capture_logs() -> (CapturedLogs, DefaultGuard) {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(logs.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    #[test]
+    fn redacts_sensitive_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer hunter2".parse().unwrap());
+        headers.insert("x-telegram-bot-api-secret-token", "s3cret".parse().unwrap());
+        headers.insert(header::COOKIE, "session=abc".parse().unwrap());
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+
+        assert_eq!(
+            redacted(&headers),
+            [
+                ("authorization", "<redacted>"),
+                ("x-telegram-bot-api-secret-token", "<redacted>"),
+                ("cookie", "<redacted>"),
+                ("content-type", "application/json"),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncates_long_bodies() {
+        assert_eq!(truncated(b"hello", 5), "hello");
+        assert_eq!(truncated(b"hello world", 5), "hello... (11 bytes total)");
+        assert_eq!(truncated(b"", 0), "");
+    }
+
+    #[tokio::test]
+    async fn logs_path_and_body_but_not_credentials() {
+        let (logs, _guard) = capture_logs();
+
+        let response = test_app()
+            .oneshot(
+                Request::post("/webhook")
+                    .header(header::AUTHORIZATION, "Bearer hunter2")
+                    .header("x-telegram-bot-api-secret-token", "s3cret")
+                    .body(Body::from("update 42"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "update 42");
+
+        let logs = logs.contents();
+        assert!(logs.contains("/webhook"), "{logs}");
+        assert!(logs.contains("update 42"), "{logs}");
+        assert!(logs.contains("<redacted>"), "{logs}");
+        assert!(!logs.contains("hunter2"), "{logs}");
+        assert!(!logs.contains("s3cret"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let response = test_app()
+            .oneshot(
+                Request::post("/webhook")
+                    .body(Body::from(vec![b'x'; 2048]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn broken_bodies_are_not_reported_as_too_large() {
+        let (mut sender, body) = Body::channel();
+        sender.send_data("first chunk".into()).await.unwrap();
+        // As when the client disconnects halfway through
+        sender.abort();
+
+        let response = test_app()
+            .oneshot(Request::post("/webhook").body(body).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn large_responses_pass_through_unbuffered() {
+        let (logs, _guard) = capture_logs();
+
+        let response = test_app()
+            .oneshot(Request::get("/large").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), 2048);
+
+        let logs = logs.contents();
+        assert!(logs.contains("<not logged>"), "{logs}");
+        assert!(!logs.contains("xxxx"), "{logs}");
+    }
+}
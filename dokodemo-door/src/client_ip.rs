@@ -0,0 +1,288 @@
+//! Resolve the real client address when running behind reverse proxies
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+/// Address of the client that made the request, stored in request extensions.
+///
+/// Absent when the peer is a unix socket and no forwarding header was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Proxies whose `X-Forwarded-For`/`X-Real-IP` headers are believed, parsed
+/// from a comma-separated `TRUSTED_PROXIES`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies(Vec<IpAddr>);
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid trusted proxy address {0:?}")]
+pub struct ParseTrustedProxiesError(String);
+
+impl FromStr for TrustedProxies {
+    type Err = ParseTrustedProxiesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| {
+                addr.parse::<IpAddr>()
+                    .map(|addr| addr.to_canonical())
+                    .map_err(|_| ParseTrustedProxiesError(addr.to_string()))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl TrustedProxies {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+}
+
+/// Middleware inserting a [`ClientIp`] into the request extensions.
+///
+/// Forwarding headers are only honoured when the socket peer is a trusted
+/// proxy, or a unix socket (only local processes such as the proxy itself can
+/// connect to one). Otherwise the socket peer is the client.
+pub async fn resolve_client_ip<B>(
+    State(trusted): State<Arc<TrustedProxies>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    if let Some(client) = client_ip(peer, request.headers(), &trusted) {
+        request.extensions_mut().insert(ClientIp(client));
+    }
+    next.run(request).await
+}
+
+/// The client behind `peer`, the socket peer address (`None` for unix sockets).
+///
+/// Addresses are compared in canonical form: on a dual-stack `[::]` listener
+/// IPv4 peers show up as IPv4-mapped IPv6 addresses such as `::ffff:10.0.0.1`.
+fn client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted: &TrustedProxies,
+) -> Option<IpAddr> {
+    let peer = peer.map(|peer| peer.to_canonical());
+
+    match peer {
+        Some(peer) if !trusted.contains(&peer) => Some(peer),
+        _ => forwarded_client(headers, trusted).or(peer),
+    }
+}
+
+/// The client according to forwarding headers set by trusted proxies.
+///
+/// `X-Forwarded-For` is walked from the right, skipping trusted proxies, so a
+/// client can't spoof its address by prepending entries.
+fn forwarded_client(headers: &HeaderMap, trusted: &TrustedProxies) -> Option<IpAddr> {
+    let forwarded_for = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+
+    if !forwarded_for.is_empty() {
+        return forwarded_for
+            .into_iter()
+            .rev()
+            .map_while(|addr| addr.parse::<IpAddr>().ok())
+            .map(|addr| addr.to_canonical())
+            .find(|addr| !trusted.contains(addr));
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok())
+        .map(|addr| addr.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::Extension, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    const PROXY: &str = "10.0.0.1";
+    const CLIENT: &str = "203.0.113.7";
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn trusted(proxies: &str) -> TrustedProxies {
+        proxies.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let headers = headers(&[("x-forwarded-for", "1.2.3.4"), ("x-real-ip", "1.2.3.4")]);
+
+        assert_eq!(
+            client_ip(Some(ip(CLIENT)), &headers, &trusted(PROXY)),
+            Some(ip(CLIENT))
+        );
+        assert_eq!(
+            client_ip(Some(ip(CLIENT)), &headers, &TrustedProxies::default()),
+            Some(ip(CLIENT))
+        );
+    }
+
+    #[test]
+    fn trusted_peer_walks_forwarded_for_past_trusted_hops() {
+        let trusted = trusted("10.0.0.1, 10.0.0.2");
+        let headers = headers(&[("x-forwarded-for", "203.0.113.7, 10.0.0.2")]);
+
+        assert_eq!(
+            client_ip(Some(ip(PROXY)), &headers, &trusted),
+            Some(ip(CLIENT))
+        );
+    }
+
+    #[test]
+    fn spoofed_leftmost_entry_is_ignored() {
+        // The client sent `X-Forwarded-For: 1.2.3.4`, the proxy appended the real address
+        let headers = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.7")]);
+
+        assert_eq!(
+            client_ip(Some(ip(PROXY)), &headers, &trusted(PROXY)),
+            Some(ip(CLIENT))
+        );
+    }
+
+    #[test]
+    fn garbage_in_forwarded_for_stops_the_walk() {
+        let headers = headers(&[("x-forwarded-for", "203.0.113.7, garbage")]);
+
+        assert_eq!(
+            client_ip(Some(ip(PROXY)), &headers, &trusted(PROXY)),
+            Some(ip(PROXY))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_real_ip() {
+        let headers = headers(&[("x-real-ip", " 203.0.113.7 ")]);
+
+        assert_eq!(
+            client_ip(Some(ip(PROXY)), &headers, &trusted(PROXY)),
+            Some(ip(CLIENT))
+        );
+        // Unix socket peers are trusted implicitly
+        assert_eq!(
+            client_ip(None, &headers, &TrustedProxies::default()),
+            Some(ip(CLIENT))
+        );
+    }
+
+    #[test]
+    fn trusted_peer_without_headers_is_the_client() {
+        assert_eq!(
+            client_ip(Some(ip(PROXY)), &HeaderMap::new(), &trusted(PROXY)),
+            Some(ip(PROXY))
+        );
+        assert_eq!(
+            client_ip(None, &HeaderMap::new(), &TrustedProxies::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_their_ipv4_form() {
+        // A dual-stack `[::]` listener reports IPv4 peers as IPv4-mapped
+        assert_eq!(
+            client_ip(
+                Some(ip("::ffff:10.0.0.1")),
+                &headers(&[("x-forwarded-for", CLIENT)]),
+                &trusted(PROXY)
+            ),
+            Some(ip(CLIENT))
+        );
+
+        let headers = headers(&[("x-forwarded-for", "::ffff:203.0.113.7, ::ffff:10.0.0.2")]);
+
+        assert_eq!(
+            client_ip(
+                Some(ip("::ffff:10.0.0.1")),
+                &headers,
+                &trusted("10.0.0.1, 10.0.0.2")
+            ),
+            Some(ip(CLIENT))
+        );
+        // Also when the mapped form is what was configured
+        assert_eq!(
+            client_ip(
+                Some(ip(PROXY)),
+                &headers,
+                &trusted("::ffff:10.0.0.1, 10.0.0.2")
+            ),
+            Some(ip(CLIENT))
+        );
+    }
+
+    #[test]
+    fn parses_trusted_proxies() {
+        assert_eq!(
+            trusted(" 10.0.0.1, ::1,,"),
+            TrustedProxies(vec![ip("10.0.0.1"), ip("::1")])
+        );
+        assert_eq!(trusted(""), TrustedProxies::default());
+        for proxies in ["localhost", "10.0.0.1, 10.0.0", "10.0.0.0/8", "10.0.0.1:80"] {
+            assert!(proxies.parse::<TrustedProxies>().is_err(), "{proxies:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn middleware_inserts_client_ip() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(ClientIp(ip)): Extension<ClientIp>| async move { ip.to_string() }),
+            )
+            .layer(middleware::from_fn_with_state(
+                Arc::new(trusted(PROXY)),
+                resolve_client_ip,
+            ));
+
+        let response = app
+            .oneshot(
+                Request::get("/")
+                    .extension(ConnectInfo(SocketAddr::new(ip(PROXY), 4000)))
+                    .header("x-forwarded-for", CLIENT)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, CLIENT);
+    }
+}
@@ -0,0 +1,152 @@
+//! Runtime configuration, read from the environment
+
+use crate::{client_ip::TrustedProxies, listen::ListenAddr};
+use anyhow::{Context, Result};
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Default log filter, used when neither `LOG_LEVEL` nor `RUST_LOG` is set
+const DEFAULT_LOG_LEVEL: &str = "dokodemo_door=debug,tower_http=debug";
+
+/// Default address to listen on
+const DEFAULT_LISTEN_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000);
+
+/// Default cap on request bodies, ~5mb
+const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 5_000;
+/// Default time budget for ordinary requests, in seconds
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 10;
+/// Default time budget for uploads, in seconds
+const DEFAULT_UPLOAD_TIMEOUT_SECS: u64 = 60;
+/// Default number of body bytes written to the log by `LOG_BODIES`
+const DEFAULT_LOG_BODY_LIMIT: usize = 4096;
+/// Default grace period for in-flight requests on shutdown, in seconds
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+/// Default bearer token for the admin routes, only fit for local development
+const DEFAULT_ADMIN_TOKEN: &str = "secret-token";
+
+pub struct Config {
+    /// Log line format
+    pub log_format: LogFormat,
+    /// `tracing_subscriber::EnvFilter` directives, e.g. `info,tower_http=debug`
+    pub log_level: String,
+    /// Log request and response bodies at debug level
+    pub log_bodies: bool,
+    /// Bytes of each body written to the log when `log_bodies` is on
+    pub log_body_limit: usize,
+    /// TCP address or unix socket the server accepts connections on
+    pub listen_addr: ListenAddr,
+    /// Reverse proxies allowed to report the client address in forwarding headers
+    pub trusted_proxies: TrustedProxies,
+    /// Largest request body accepted before answering `413 Payload Too Large`
+    pub max_body_bytes: usize,
+    /// Time budget for ordinary requests before answering `504 Gateway Timeout`
+    pub request_timeout: Duration,
+    /// Time budget for routes receiving large bodies, such as `kv_set`
+    pub upload_timeout: Duration,
+    /// Grace period given to in-flight requests once shutdown begins
+    pub drain_timeout: Duration,
+    /// Bearer token required by the `/admin` routes
+    pub admin_token: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_format: LogFormat::Pretty,
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            log_bodies: false,
+            log_body_limit: DEFAULT_LOG_BODY_LIMIT,
+            listen_addr: ListenAddr::Tcp(DEFAULT_LISTEN_ADDR),
+            trusted_proxies: TrustedProxies::default(),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            upload_timeout: Duration::from_secs(DEFAULT_UPLOAD_TIMEOUT_SECS),
+            drain_timeout: Duration::from_secs(DEFAULT_DRAIN_TIMEOUT_SECS),
+            admin_token: DEFAULT_ADMIN_TOKEN.to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration, using the defaults for unset variables.
+    pub fn from_env() -> Result<Self> {
+        let default = Self::default();
+
+        Ok(Self {
+            log_format: parse_env("LOG_FORMAT", default.log_format)?,
+            log_level: env::var("LOG_LEVEL")
+                .or_else(|_| env::var("RUST_LOG"))
+                .unwrap_or(default.log_level),
+            log_bodies: parse_env("LOG_BODIES", default.log_bodies)?,
+            log_body_limit: parse_env("LOG_BODY_LIMIT", default.log_body_limit)?,
+            listen_addr: parse_env("LISTEN_ADDR", default.listen_addr)?,
+            trusted_proxies: parse_env("TRUSTED_PROXIES", default.trusted_proxies)?,
+            max_body_bytes: parse_env("MAX_BODY_BYTES", default.max_body_bytes)?,
+            request_timeout: parse_secs("REQUEST_TIMEOUT_SECS", default.request_timeout)?,
+            upload_timeout: parse_secs("UPLOAD_TIMEOUT_SECS", default.upload_timeout)?,
+            drain_timeout: parse_secs("DRAIN_TIMEOUT_SECS", default.drain_timeout)?,
+            admin_token: env::var("ADMIN_TOKEN").unwrap_or(default.admin_token),
+        })
+    }
+}
+
+/// How log lines are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One JSON object per line, for log aggregators
+    Json,
+    /// Human-readable lines for local development
+    Pretty,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown log format {0:?}, expected `json` or `pretty`")]
+pub struct ParseLogFormatError(String);
+
+impl FromStr for LogFormat {
+    type Err = ParseLogFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "pretty" => Ok(Self::Pretty),
+            _ => Err(ParseLogFormatError(s.to_string())),
+        }
+    }
+}
+
+/// Parse `key` from the environment, falling back to `default` when unset.
+fn parse_env<T>(key: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("invalid {key}: {value:?}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parse a whole number of seconds from `key`, falling back to `default`.
+fn parse_secs(key: &str, default: Duration) -> Result<Duration> {
+    parse_env(key, default.as_secs()).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_log_formats() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("PRETTY".parse::<LogFormat>().unwrap(), LogFormat::Pretty);
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert!("".parse::<LogFormat>().is_err());
+    }
+}
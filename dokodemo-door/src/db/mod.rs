@@ -1,4 +1,4 @@
-mod postgre;
-mod sqlite;
+pub mod postgre;
+pub mod sqlite;
 
 pub trait Repository {}
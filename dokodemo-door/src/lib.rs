@@ -1,3 +1,3 @@
 //! Backend for takekoputaa
 
-mod db;
\ No newline at end of file
+pub mod db;
@@ -0,0 +1,220 @@
+//! Listen address parsing and binding
+
+use anyhow::{Context, Result};
+use axum::Router;
+use socket2::{Domain, Socket, Type};
+use std::{
+    fmt,
+    future::Future,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+};
+
+/// Where the server accepts connections, parsed from `LISTEN_ADDR`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// `host:port`; `[::]:port` accepts both IPv6 and IPv4
+    Tcp(SocketAddr),
+    /// `unix:/path/to.sock`, for running behind a reverse proxy
+    Unix(PathBuf),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid listen address {0:?}, expected `host:port` or `unix:/path/to.sock`")]
+pub struct ParseListenAddrError(String);
+
+impl FromStr for ListenAddr {
+    type Err = ParseListenAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(ParseListenAddrError(s.to_string()));
+            }
+            return Ok(Self::Unix(path.into()));
+        }
+
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|_| ParseListenAddrError(s.to_string()))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub type ServerFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Bind `addr` and return the future serving `app` until `signal` resolves.
+pub fn serve<F>(addr: &ListenAddr, app: Router, signal: F) -> Result<ServerFuture>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            let server = axum::Server::from_tcp(bind_tcp(addr)?)
+                .with_context(|| format!("failed to listen on {addr}"))?
+                // Expose the peer address for `client_ip`
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(signal);
+            Ok(Box::pin(async move { Ok(server.await?) }))
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            remove_stale_socket(path)?;
+            let listener = tokio::net::UnixListener::bind(path)
+                .with_context(|| format!("failed to bind unix socket {}", path.display()))?;
+            let accept = hyper::server::accept::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|result| Some(result.map(|(stream, _)| stream)))
+            });
+            let server = axum::Server::builder(accept)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(signal);
+            // Owned by the future, so the socket file also goes away when the
+            // server is abandoned after the drain timeout
+            let socket_file = SocketFile(path.clone());
+            Ok(Box::pin(async move {
+                let _socket_file = socket_file;
+                Ok(server.await?)
+            }))
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => anyhow::bail!("unix sockets are not supported on this platform"),
+    }
+}
+
+/// Removes the unix socket file when dropped.
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Remove a socket file left behind by a crashed or killed server. A socket
+/// that still accepts connections belongs to a live server and is kept.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                anyhow::bail!("unix socket {} is in use by another server", path.display());
+            }
+            std::fs::remove_file(path)
+                .with_context(|| format!("failed to remove stale socket {}", path.display()))
+        }
+        // Missing, or not a socket: leave it for `bind` to report
+        _ => Ok(()),
+    }
+}
+
+/// Bind a TCP listener, making `[::]` dual-stack regardless of the OS default.
+fn bind_tcp(addr: &SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)
+        .context("failed to create socket")?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket
+            .set_only_v6(false)
+            .context("failed to enable dual-stack listening")?;
+    }
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&(*addr).into())
+        .with_context(|| format!("failed to bind {addr}"))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv6Addr, SocketAddrV6};
+
+    #[test]
+    fn parses_tcp_addresses() {
+        assert_eq!(
+            "[::]:8080".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 8080, 0, 0).into())
+        );
+        assert_eq!(
+            "127.0.0.1:3000".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp(([127, 0, 0, 1], 3000).into())
+        );
+    }
+
+    #[test]
+    fn parses_unix_sockets() {
+        assert_eq!(
+            "unix:/run/app.sock".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Unix("/run/app.sock".into())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for addr in ["foo", "unix:", "127.0.0.1", "[::]", ""] {
+            assert!(addr.parse::<ListenAddr>().is_err(), "{addr:?}");
+        }
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dokodemo-{}-{name}.sock", std::process::id()))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn replaces_stale_socket_and_removes_it_when_dropped() {
+        let path = socket_path("stale");
+        let _ = std::fs::remove_file(&path);
+        // A listener that went away without cleaning up, like after SIGKILL
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let server = serve(
+            &ListenAddr::Unix(path.clone()),
+            Router::new(),
+            std::future::pending(),
+        )
+        .unwrap();
+        assert!(path.exists());
+
+        // Abandoned without ever completing, as when the drain timeout wins
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keeps_socket_of_live_server() {
+        let path = socket_path("live");
+        let _ = std::fs::remove_file(&path);
+        let _live = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let result = serve(
+            &ListenAddr::Unix(path.clone()),
+            Router::new(),
+            std::future::pending(),
+        );
+        assert!(result.is_err());
+        assert!(path.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,143 +1,119 @@
-use anyhow::Result;
-use axum::{
-    body::Bytes,
-    error_handling::HandleErrorLayer,
-    extract::{DefaultBodyLimit, Path, State},
-    handler::Handler,
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{delete, get},
-    Router,
+mod auth;
+mod body_log;
+mod client_ip;
+mod config;
+mod listen;
+mod routes;
+mod shutdown;
+#[cfg(test)]
+mod test_util;
+
+use anyhow::{Context, Result};
+use config::{Config, LogFormat};
+use routes::SharedState;
+use tokio::sync::oneshot;
+use tracing::Subscriber;
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+    EnvFilter, Layer,
 };
-use std::{
-    borrow::Cow,
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, RwLock},
-    time::Duration,
-};
-use tower::{BoxError, ServiceBuilder};
-use tower_http::{
-    compression::CompressionLayer, limit::RequestBodyLimitLayer, trace::TraceLayer,
-    validate_request::ValidateRequestHeaderLayer,
-};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "dokodemo_door=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let shared_state = SharedState::default();
-
-    // Build our application by composing routes
-    let app = Router::new()
-        .route(
-            "/:key",
-            // Add compression to `kv_get`
-            get(kv_get.layer(CompressionLayer::new()))
-                // But don't compress `kv_set`
-                .post_service(
-                    kv_set
-                        .layer((
-                            DefaultBodyLimit::disable(),
-                            RequestBodyLimitLayer::new(1024 * 5_000 /* ~5mb */),
-                        ))
-                        .with_state(Arc::clone(&shared_state)),
-                ),
-        )
-        .route("/keys", get(list_keys))
-        // Nest our admin routes under `/admin`
-        .nest("/admin", admin_routes())
-        // Add middleware to all routes
-        .layer(
-            ServiceBuilder::new()
-                // Handle errors from middleware
-                .layer(HandleErrorLayer::new(handle_error))
-                .load_shed()
-                .concurrency_limit(1024)
-                .timeout(Duration::from_secs(10))
-                .layer(TraceLayer::new_for_http()),
-        )
-        .with_state(Arc::clone(&shared_state));
-
-    // Run our app with hyper
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    tracing::debug!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    let config = Config::from_env()?;
+    init_tracing(&config)?;
+
+    let state = SharedState::default();
+    let in_flight = state.read().unwrap().in_flight();
+    let app = routes::app(&config, state);
+
+    // Run our app with hyper, draining in-flight requests on shutdown
+    let (draining_tx, draining_rx) = oneshot::channel();
+    let server = listen::serve(
+        &config.listen_addr,
+        app,
+        shutdown::signal(in_flight.clone(), draining_tx),
+    )?;
+    tracing::debug!("listening on {}", config.listen_addr);
+
+    tokio::select! {
+        result = server => result?,
+        _ = shutdown::drain_deadline(draining_rx, config.drain_timeout) => {
+            tracing::warn!(
+                "drain timeout elapsed, abandoning {} in-flight requests",
+                in_flight.count()
+            );
+        }
+    }
+    tracing::debug!("server stopped");
     Ok(())
 }
 
-type SharedState = Arc<RwLock<AppState>>;
+/// Install the global subscriber, before anything is logged.
+fn init_tracing(config: &Config) -> Result<()> {
+    let filter = EnvFilter::try_new(&config.log_level)
+        .with_context(|| format!("invalid LOG_LEVEL: {:?}", config.log_level))?;
 
-#[derive(Default)]
-struct AppState {
-    db: HashMap<String, Bytes>,
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer(config.log_format, std::io::stdout))
+        .init();
+    Ok(())
 }
 
-async fn kv_get(
-    Path(key): Path<String>,
-    State(state): State<SharedState>,
-) -> Result<Bytes, StatusCode> {
-    let db = &state.read().unwrap().db;
-
-    if let Some(value) = db.get(&key) {
-        Ok(value.clone())
-    } else {
-        Err(StatusCode::NOT_FOUND)
+/// The layer formatting log lines as `format`, written to `writer`.
+fn fmt_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+
+    match format {
+        LogFormat::Json => layer.json().boxed(),
+        LogFormat::Pretty => layer.boxed(),
     }
 }
 
-async fn kv_set(Path(key): Path<String>, State(state): State<SharedState>, bytes: Bytes) {
-    state.write().unwrap().db.insert(key, bytes);
-}
-
-async fn list_keys(State(state): State<SharedState>) -> String {
-    let db = &state.read().unwrap().db;
-
-    db.keys()
-        .map(|key| key.to_string())
-        .collect::<Vec<String>>()
-        .join("\n")
-}
-
-fn admin_routes() -> Router<SharedState> {
-    async fn delete_all_keys(State(state): State<SharedState>) {
-        state.write().unwrap().db.clear();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::CapturedLogs;
+
+    #[test]
+    fn json_format_emits_parseable_lines() {
+        let logs = CapturedLogs::default();
+        let subscriber =
+            tracing_subscriber::registry().with(fmt_layer(LogFormat::Json, logs.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(answer = 42, "hello");
+            tracing::info_span!("request", path = "/v1/keys").in_scope(|| {
+                tracing::debug!("inside");
+            });
+        });
+
+        let lines = logs
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["fields"]["message"], "hello");
+        assert_eq!(lines[0]["fields"]["answer"], 42);
+        assert_eq!(lines[1]["span"]["path"], "/v1/keys");
     }
 
-    async fn remove_key(Path(key): Path<String>, State(state): State<SharedState>) {
-        state.write().unwrap().db.remove(&key);
-    }
+    #[test]
+    fn pretty_format_is_not_json() {
+        let logs = CapturedLogs::default();
+        let subscriber =
+            tracing_subscriber::registry().with(fmt_layer(LogFormat::Pretty, logs.clone()));
 
-    Router::new()
-        .route("/keys", delete(delete_all_keys))
-        .route("/key/:key", delete(remove_key))
-        // Require bearer auth for all admin routes
-        .layer(ValidateRequestHeaderLayer::bearer("secret-token"))
-}
+        tracing::subscriber::with_default(subscriber, || tracing::info!("hello"));
 
-async fn handle_error(error: BoxError) -> impl IntoResponse {
-    if error.is::<tower::timeout::error::Elapsed>() {
-        return (StatusCode::REQUEST_TIMEOUT, Cow::from("request timed out"));
+        let contents = logs.contents();
+        assert!(contents.contains("hello"));
+        assert!(serde_json::from_str::<serde_json::Value>(contents.trim()).is_err());
     }
-
-    if error.is::<tower::load_shed::error::Overloaded>() {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Cow::from("service is overloaded, try again later"),
-        );
-    }
-
-    (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        Cow::from(format!("Unhandled internal error: {}", error)),
-    )
 }
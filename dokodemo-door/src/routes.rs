@@ -0,0 +1,483 @@
+//! Route wiring and the key/value handlers
+
+use crate::{
+    auth::BearerAuth,
+    body_log::{self, BodyLog},
+    client_ip,
+    config::Config,
+    shutdown,
+    shutdown::InFlight,
+};
+use axum::{
+    body::Bytes,
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, Path, State},
+    http::StatusCode,
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tower::{BoxError, ServiceBuilder};
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    trace::TraceLayer,
+    validate_request::ValidateRequestHeaderLayer,
+};
+
+/// Responses smaller than this are sent uncompressed.
+const MIN_COMPRESS_SIZE: u16 = 1024;
+
+pub type SharedState = Arc<RwLock<AppState>>;
+
+pub struct AppState {
+    db: HashMap<String, Bytes>,
+    started_at: Instant,
+    in_flight: InFlight,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self {
+            db: HashMap::new(),
+            started_at: Instant::now(),
+            in_flight: InFlight::default(),
+        }
+    }
+}
+
+impl AppState {
+    /// The counter of requests being handled, shared with graceful shutdown.
+    pub fn in_flight(&self) -> InFlight {
+        self.in_flight.clone()
+    }
+}
+
+/// Build the application router with all routes and middleware.
+pub fn app(config: &Config, state: SharedState) -> Router {
+    let in_flight = state.read().unwrap().in_flight();
+
+    // Build our application by composing routes
+    Router::new()
+        // Nest our admin routes under `/admin`
+        .nest(
+            "/admin",
+            with_timeout(admin_routes(config), config.request_timeout),
+        )
+        // Version the public API, later versions get nested alongside
+        .nest("/v1", v1_routes(config))
+        // Log request and response bodies when asked to, for debugging integrations
+        .layer(middleware::from_fn_with_state(
+            BodyLog {
+                enabled: config.log_bodies,
+                log_limit: config.log_body_limit,
+                max_body_bytes: config.max_body_bytes,
+            },
+            body_log::log_bodies,
+        ))
+        // Reject bodies over the configured size with `413 Payload Too Large`
+        .layer(DefaultBodyLimit::max(config.max_body_bytes))
+        // Resolve the real client address into a `ClientIp` extension
+        .layer(middleware::from_fn_with_state(
+            Arc::new(config.trusted_proxies.clone()),
+            client_ip::resolve_client_ip,
+        ))
+        // Add middleware to all routes
+        .layer(
+            ServiceBuilder::new()
+                // Handle errors from middleware
+                .layer(HandleErrorLayer::new(handle_error))
+                .load_shed()
+                .concurrency_limit(1024)
+                // Backstop for paths outside the per-route timeouts, such as
+                // fallbacks and the middleware below
+                .timeout(config.request_timeout.max(config.upload_timeout))
+                .layer(TraceLayer::new_for_http())
+                // Compress responses negotiated via `Accept-Encoding`, skipping tiny ones
+                .layer(
+                    CompressionLayer::new().compress_when(
+                        SizeAbove::new(MIN_COMPRESS_SIZE)
+                            .and(NotForContentType::GRPC)
+                            .and(NotForContentType::IMAGES),
+                    ),
+                ),
+        )
+        // Count requests so shutdown knows what it is waiting on
+        .layer(middleware::from_fn_with_state(
+            in_flight,
+            shutdown::track_in_flight,
+        ))
+        .with_state(state)
+}
+
+/// Version 1 of the public key/value API.
+fn v1_routes(config: &Config) -> Router<SharedState> {
+    let reads = Router::new()
+        .route("/:key", get(kv_get))
+        .route("/keys", get(list_keys));
+    // `kv_set` gets a longer budget for large uploads
+    let writes = Router::new().route("/:key", post(kv_set));
+
+    with_timeout(reads, config.request_timeout).merge(with_timeout(writes, config.upload_timeout))
+}
+
+/// Time out every route of `router` after `timeout`, answering `504 Gateway Timeout`.
+fn with_timeout<S>(router: Router<S>, timeout: Duration) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.route_layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(handle_error))
+            .timeout(timeout),
+    )
+}
+
+async fn kv_get(
+    Path(key): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<Bytes, StatusCode> {
+    let db = &state.read().unwrap().db;
+
+    if let Some(value) = db.get(&key) {
+        Ok(value.clone())
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn kv_set(Path(key): Path<String>, State(state): State<SharedState>, bytes: Bytes) {
+    state.write().unwrap().db.insert(key, bytes);
+}
+
+async fn list_keys(State(state): State<SharedState>) -> String {
+    let db = &state.read().unwrap().db;
+
+    db.keys()
+        .map(|key| key.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn admin_routes(config: &Config) -> Router<SharedState> {
+    async fn delete_all_keys(State(state): State<SharedState>) {
+        state.write().unwrap().db.clear();
+    }
+
+    async fn remove_key(Path(key): Path<String>, State(state): State<SharedState>) {
+        state.write().unwrap().db.remove(&key);
+    }
+
+    /// Runtime diagnostics for humans, a lighter complement to proper metrics.
+    async fn debug(State(state): State<SharedState>) -> Json<DebugInfo> {
+        let state = state.read().unwrap();
+
+        Json(DebugInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: state.started_at.elapsed().as_secs(),
+            in_flight_requests: state.in_flight.count(),
+            keys: state.db.len(),
+        })
+    }
+
+    Router::new()
+        .route("/keys", delete(delete_all_keys))
+        .route("/key/:key", delete(remove_key))
+        .route("/debug", get(debug))
+        // Require bearer auth for all admin routes
+        .layer(ValidateRequestHeaderLayer::custom(BearerAuth::new(
+            &config.admin_token,
+        )))
+}
+
+#[derive(Serialize)]
+struct DebugInfo {
+    version: &'static str,
+    uptime_secs: u64,
+    in_flight_requests: usize,
+    keys: usize,
+}
+
+async fn handle_error(error: BoxError) -> impl IntoResponse {
+    if error.is::<tower::timeout::error::Elapsed>() {
+        return (StatusCode::GATEWAY_TIMEOUT, Cow::from("request timed out"));
+    }
+
+    if error.is::<tower::load_shed::error::Overloaded>() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Cow::from("service is overloaded, try again later"),
+        );
+    }
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Cow::from(format!("Unhandled internal error: {}", error)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+    use tower::ServiceExt;
+
+    fn test_app(config: &Config) -> Router {
+        app(config, SharedState::default())
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_gzipped() {
+        let app = test_app(&Config::default());
+        let value = "doraemon ".repeat(1024);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/v1/big")
+                    .body(Body::from(value.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::get("/v1/big")
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    }
+
+    #[tokio::test]
+    async fn small_responses_are_not_compressed() {
+        let app = test_app(&Config::default());
+
+        app.clone()
+            .oneshot(Request::post("/v1/small").body(Body::from("hi")).unwrap())
+            .await
+            .unwrap();
+        let response = app
+            .oneshot(
+                Request::get("/v1/small")
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        for log_bodies in [false, true] {
+            let config = Config {
+                max_body_bytes: 16,
+                log_bodies,
+                ..Config::default()
+            };
+
+            let response = test_app(&config)
+                .oneshot(
+                    Request::post("/v1/big")
+                        .body(Body::from([0; 17].to_vec()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "log_bodies = {log_bodies}"
+            );
+
+            let response = test_app(&config)
+                .oneshot(
+                    Request::post("/v1/fits")
+                        .body(Body::from([0; 16].to_vec()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::OK,
+                "log_bodies = {log_bodies}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_handlers_time_out() {
+        let app = with_timeout(
+            Router::new().route(
+                "/slow",
+                get(|| async { tokio::time::sleep(Duration::from_secs(5)).await }),
+            ),
+            Duration::from_millis(20),
+        );
+
+        let response = app
+            .oneshot(Request::get("/slow").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn stalled_bodies_time_out_while_logging_bodies() {
+        let app = test_app(&Config {
+            log_bodies: true,
+            request_timeout: Duration::from_millis(20),
+            upload_timeout: Duration::from_millis(20),
+            ..Config::default()
+        });
+        let (mut sender, body) = Body::channel();
+        sender.send_data("first chunk".into()).await.unwrap();
+
+        // The sender stays alive but never finishes the body, which the body
+        // logger buffers before any per-route timeout starts
+        let response = app
+            .oneshot(Request::post("/v1/doraemon").body(body).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn fallback_still_answers_within_the_backstop() {
+        let response = test_app(&Config::default())
+            .oneshot(Request::get("/missing").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn debug_reports_keys_and_in_flight_requests() {
+        let app = test_app(&Config::default());
+
+        app.clone()
+            .oneshot(
+                Request::post("/v1/doraemon")
+                    .body(Body::from("nobita"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/admin/debug").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .oneshot(
+                Request::get("/admin/debug")
+                    .header(header::AUTHORIZATION, "Bearer secret-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["keys"], 1);
+        // The debug request itself, counted by the same tracker shutdown waits on
+        assert_eq!(info["in_flight_requests"], 1);
+        assert!(info["uptime_secs"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn api_is_only_served_under_v1() {
+        let app = test_app(&Config::default());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/v1/doraemon")
+                    .body(Body::from("nobita"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/v1/keys").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "doraemon");
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/v1/doraemon").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The unversioned paths are gone, even for a key that exists
+        for path in ["/keys", "/doraemon"] {
+            let response = app
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_token_comes_from_config() {
+        let app = test_app(&Config {
+            admin_token: "from-config".to_string(),
+            ..Config::default()
+        });
+
+        for (token, status) in [
+            ("secret-token", StatusCode::UNAUTHORIZED),
+            ("from-config", StatusCode::OK),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::get("/admin/debug")
+                        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{token}");
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Graceful shutdown: stop accepting connections, then drain in-flight requests
+
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{signal, sync::oneshot};
+
+/// Number of requests currently being handled
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Decrements the counter when dropped, so cancelled requests are released too.
+struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware counting requests for the duration of their handling.
+pub async fn track_in_flight<B>(
+    State(in_flight): State<InFlight>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    in_flight.0.fetch_add(1, Ordering::SeqCst);
+    let _guard = InFlightGuard(in_flight);
+    next.run(request).await
+}
+
+/// Resolves on a shutdown signal, for `with_graceful_shutdown`. Tells
+/// [`drain_deadline`] that draining has begun through `draining`.
+pub async fn signal(in_flight: InFlight, draining: oneshot::Sender<()>) {
+    shutdown_signal().await;
+    tracing::info!(
+        "shutdown signal received, draining {} in-flight requests",
+        in_flight.count()
+    );
+    let _ = draining.send(());
+}
+
+/// Resolves once `drain_timeout` has elapsed after draining began, so the
+/// server can be abandoned instead of waiting forever on slow requests.
+pub async fn drain_deadline(draining: oneshot::Receiver<()>, drain_timeout: Duration) {
+    match draining.await {
+        Ok(()) => tokio::time::sleep(drain_timeout).await,
+        // The server stopped without a signal, nothing to drain
+        Err(_) => std::future::pending().await,
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get, Router};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    };
+
+    #[tokio::test]
+    async fn drain_deadline_waits_for_draining_to_begin() {
+        let (draining_tx, draining_rx) = oneshot::channel::<()>();
+        drop(draining_tx);
+
+        let deadline = drain_deadline(draining_rx, Duration::from_millis(1));
+        assert!(timeout(Duration::from_millis(50), deadline).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_deadline_elapses_after_draining_begins() {
+        let (draining_tx, draining_rx) = oneshot::channel();
+        draining_tx.send(()).unwrap();
+
+        let deadline = drain_deadline(draining_rx, Duration::from_millis(10));
+        assert!(timeout(Duration::from_secs(1), deadline).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn request_started_before_shutdown_completes() {
+        let in_flight = InFlight::default();
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    "done"
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                in_flight.clone(),
+                track_in_flight,
+            ));
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        let server = tokio::spawn(server.with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        }));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        while in_flight.count() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        shutdown_tx.send(()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+
+        timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server drained")
+            .unwrap()
+            .unwrap();
+        assert_eq!(in_flight.count(), 0);
+    }
+}